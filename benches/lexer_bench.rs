@@ -0,0 +1,49 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use octoproc_assembler::lexer::Lexer;
+
+// A synthetic program big enough to make per-token overhead visible:
+// symbols, radix-prefixed numbers, and a string literal, repeated.
+fn sample_source(lines: usize) -> String {
+	let mut source = String::new();
+	for i in 0..lines {
+		source.push_str(&format!(
+			"label{i}: add 0x1F, 0b101, r{i} ; comment\n\"payload {i}\"\n"
+		));
+	}
+	source
+}
+
+fn bench_tokenize(c: &mut Criterion) {
+	let source = sample_source(2000);
+
+	c.bench_function("lex_one full pass", |b| {
+		b.iter(|| {
+			let lexer = Lexer::new("bench", &source);
+			for token in lexer {
+				black_box(token);
+			}
+		})
+	});
+}
+
+// Exercises peek() on every token before advancing, the pattern a
+// lookahead parser uses. Before the peek cache this cost a full
+// lex-then-rollback per token in addition to the real lex; now it costs
+// one lex shared between the peek and the following next().
+fn bench_peek_then_next(c: &mut Criterion) {
+	let source = sample_source(2000);
+
+	c.bench_function("peek() + next() per token", |b| {
+		b.iter(|| {
+			let mut lexer = Lexer::new("bench", &source);
+			while let Some(peeked) = lexer.peek() {
+				black_box(&peeked);
+				black_box(lexer.next());
+			}
+		})
+	});
+}
+
+criterion_group!(benches, bench_tokenize, bench_peek_then_next);
+criterion_main!(benches);
@@ -1,7 +1,14 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use chardetng::EncodingDetector;
+use encoding_rs::Encoding;
+
 use crate::{u9, u12};
 
 // Represents the value of the token.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum TokenValue {
 	// No token
 	None,
@@ -28,18 +35,324 @@ pub enum TokenValue {
 	String(String),
 }
 
+// Represents the byte range a token was lexed from, so diagnostics can
+// underline the whole symbol/number/string instead of a single column.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+	pub start: usize,
+	pub end: usize,
+	pub lino: usize,
+}
+
 // Represents a token.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Token {
 	// Where the token was generated
 	pub pos: usize,
 	pub lino: usize,
 	pub charpos: usize,
 
+	// The byte range the token was lexed from
+	pub span: Span,
+
 	// The type of the token
 	pub value: TokenValue,
 }
 
+// Represents a single lex-time error. Errors are collected here instead of
+// aborting the token stream, so a whole pass can be lexed and every problem
+// with the input reported at once.
+#[derive(Debug, PartialEq)]
+pub struct Diagnostic {
+	pub pos: usize,
+	pub lino: usize,
+	pub charpos: usize,
+	pub message: String,
+}
+
+// Distinguishes a prompt for a brand new statement from one continuing a
+// construct (e.g. a string literal) that spans more than one read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PromptStyle {
+	First,
+	Continuation,
+}
+
+// A source of input for a `Lexer` that doesn't own its text up front,
+// e.g. a REPL reading one line at a time from a terminal
+pub trait LexRead {
+	fn read(&mut self, prompt: PromptStyle) -> String;
+}
+
+// Returned by `Cursor` lookahead once the remaining input is exhausted;
+// never a valid instruction character. A NUL inside a string literal looks
+// the same, so scanners that must tell the two apart also check `is_eof`
+const EOF_CHAR: char = '\0';
+
+// A cheap view over the remaining input. Bumping or peeking a character
+// costs O(1), and `first`/`second` lookahead needs no rescanning. A fresh
+// `Cursor` is made over the rest of `string` for each token.
+struct Cursor<'a> {
+	len_remaining: usize,
+	chars: std::str::Chars<'a>,
+}
+
+impl<'a> Cursor<'a> {
+	fn new(input: &'a str) -> Cursor<'a> {
+		Cursor {
+			len_remaining: input.len(),
+			chars: input.chars(),
+		}
+	}
+
+	// The next character, without consuming it
+	fn first(&self) -> char {
+		self.chars.clone().next().unwrap_or(EOF_CHAR)
+	}
+
+	// The character after `first`, without consuming either
+	fn second(&self) -> char {
+		let mut chars = self.chars.clone();
+		chars.next();
+		chars.next().unwrap_or(EOF_CHAR)
+	}
+
+	fn is_eof(&self) -> bool {
+		self.chars.as_str().is_empty()
+	}
+
+	// How many bytes have been consumed since this cursor was created
+	fn pos_within_token(&self) -> usize {
+		self.len_remaining - self.chars.as_str().len()
+	}
+
+	// The remaining, not-yet-consumed input
+	fn as_str(&self) -> &'a str {
+		self.chars.as_str()
+	}
+
+	// Consumes and returns the next character
+	fn bump(&mut self) -> Option<char> {
+		self.chars.next()
+	}
+
+	// Consumes characters while `predicate` holds
+	fn eat_while(&mut self, mut predicate: impl FnMut(char) -> bool) {
+		while predicate(self.first()) && !self.is_eof() {
+			self.bump();
+		}
+	}
+}
+
+// Scans the rest of a symbol (label/opcode/pragma); `first` was already
+// consumed from `cursor`
+fn scan_symbol(cursor: &mut Cursor, first: char) -> TokenValue {
+	let rest_start = cursor.as_str();
+	cursor.eat_while(|c| {
+		('a' <= c && c <= 'z') || ('A' <= c && c <= 'Z') || ('0' <= c && c <= '9') || c == '_'
+	});
+	let rest = &rest_start[..rest_start.len() - cursor.as_str().len()];
+
+	let mut s = String::with_capacity(first.len_utf8() + rest.len());
+	s.push(first);
+	s.push_str(rest);
+	TokenValue::Symbol(s)
+}
+
+// Scans a number literal from its very first digit; nothing has been
+// consumed from `cursor` yet. Recognizes an explicit 0x/0b/0o/0d radix
+// prefix by looking at the leading digit and the character after it
+// before committing to consuming either, falling back to `default_radix`
+// when there's no prefix.
+fn scan_number(cursor: &mut Cursor, default_radix: u32) -> TokenValue {
+	let mut radix = default_radix;
+	let mut digits = String::new();
+	let mut prefix_text = String::new();
+
+	let prefix_radix = if cursor.first() == '0' {
+		match cursor.second() {
+			'x' | 'X' => Some(16),
+			'b' | 'B' => Some(2),
+			'o' | 'O' => Some(8),
+			'd' | 'D' => Some(10),
+			_ => None,
+		}
+	} else {
+		None
+	};
+
+	let first = cursor.bump().unwrap();
+	prefix_text.push(first);
+
+	if let Some(r) = prefix_radix {
+		radix = r;
+		prefix_text.push(cursor.bump().unwrap());
+	} else {
+		digits.push(first);
+	}
+
+	loop {
+		let c = cursor.first();
+
+		if c.to_digit(radix).is_some() {
+			cursor.bump();
+			digits.push(c);
+
+		// A digit-like character that isn't valid for this radix (e.g.
+		// '8' in octal, '2' in binary) ends the token with an explicit
+		// error instead of silently truncating it
+		} else if c.is_ascii_alphanumeric() {
+			cursor.bump();
+			return TokenValue::Err(format!(
+				"'{}' is not a valid digit in base {} integer literal",
+				c, radix
+			));
+
+		} else {
+			break;
+		}
+	}
+
+	if digits.is_empty() {
+		return TokenValue::Err(format!(
+			"expected digits after numeric prefix in '{}'",
+			prefix_text
+		));
+	}
+
+	match u16::from_str_radix(&digits, radix) {
+		Ok(n) if n < 2u16.pow(9) => TokenValue::U9(n),
+		Ok(n) if n < 2u16.pow(12) => TokenValue::U12(n),
+		_ => TokenValue::Err(format!("'{}' is an invalid 12 bit integer", digits)),
+	}
+}
+
+// Scans a string literal's contents up to (and consuming) the closing
+// quote, processing escape sequences along the way; the opening quote was
+// already consumed from `cursor`. The returned bool is true when the
+// buffer ran out before the string closed, meaning more input from a
+// `LexRead` source could still complete it.
+fn scan_string(cursor: &mut Cursor, start_lino: usize) -> (TokenValue, bool) {
+	let mut s = String::new();
+
+	loop {
+		if cursor.is_eof() {
+			return (unterminated_string_err(start_lino), true);
+		}
+
+		let c = cursor.bump().unwrap();
+
+		match c {
+			'"' => return (TokenValue::String(s), false),
+			'\\' => match scan_escape(cursor, start_lino) {
+				Ok(decoded) => s.push(decoded),
+				Err((value, needs_more_input)) => {
+					// Consume the rest of the literal (or run off the end
+					// of the buffer) so the bad escape's span covers the
+					// whole string and the next `lex_one` call resumes
+					// after the closing quote instead of in the middle of
+					// the literal, where it would misread the remaining
+					// text as fresh tokens.
+					skip_to_string_end(cursor);
+					return (value, needs_more_input);
+				}
+			},
+			_ => s.push(c),
+		}
+	}
+}
+
+// Consumes characters up to and including the next unescaped closing
+// quote, or up to EOF if the literal never closes. Used to recover after
+// a bad escape sequence without producing phantom tokens from the
+// remainder of the literal.
+fn skip_to_string_end(cursor: &mut Cursor) {
+	while !cursor.is_eof() {
+		match cursor.bump().unwrap() {
+			'"' => return,
+			'\\' if !cursor.is_eof() => {
+				cursor.bump();
+			}
+			_ => {}
+		}
+	}
+}
+
+fn unterminated_string_err(start_lino: usize) -> TokenValue {
+	TokenValue::Err(format!(
+		"unterminated string literal starting on line {}",
+		start_lino
+	))
+}
+
+// Decodes the escape sequence right after a '\' (already consumed)
+fn scan_escape(cursor: &mut Cursor, start_lino: usize) -> Result<char, (TokenValue, bool)> {
+	if cursor.is_eof() {
+		return Err((unterminated_string_err(start_lino), true));
+	}
+
+	let c = cursor.bump().unwrap();
+
+	match c {
+		'n' => Ok('\n'),
+		't' => Ok('\t'),
+		'r' => Ok('\r'),
+		'\\' => Ok('\\'),
+		'"' => Ok('"'),
+		'x' => scan_hex_escape(cursor, start_lino),
+		// `\0` is just the 1-digit case of the octal escape below, so it
+		// must be handled here rather than as its own arm — otherwise a
+		// leading-zero octal escape like `\012` can never be reached.
+		'0'..='7' => Ok(scan_octal_escape(cursor, c)),
+		other => Err((
+			TokenValue::Err(format!(
+				"unknown escape sequence '\\{}' in string literal on line {}",
+				other, start_lino
+			)),
+			false,
+		)),
+	}
+}
+
+// Decodes a \xNN escape (exactly 2 hex digits; the 'x' was already
+// consumed)
+fn scan_hex_escape(cursor: &mut Cursor, start_lino: usize) -> Result<char, (TokenValue, bool)> {
+	let mut digits = String::new();
+
+	for _ in 0..2 {
+		let eof = cursor.is_eof();
+		let c = cursor.first();
+
+		if eof || !c.is_ascii_hexdigit() {
+			return Err((
+				TokenValue::Err(format!(
+					"truncated \\x escape in string literal on line {}",
+					start_lino
+				)),
+				eof,
+			));
+		}
+
+		cursor.bump();
+		digits.push(c);
+	}
+
+	Ok(u8::from_str_radix(&digits, 16).unwrap_or(0) as char)
+}
+
+// Decodes a \NNN escape (1 to 3 octal digits; the first was already
+// consumed)
+fn scan_octal_escape(cursor: &mut Cursor, first: char) -> char {
+	let mut digits = String::new();
+	digits.push(first);
+
+	while digits.len() < 3 && !cursor.is_eof() && ('0'..='7').contains(&cursor.first()) {
+		digits.push(cursor.bump().unwrap());
+	}
+
+	u8::from_str_radix(&digits, 8).unwrap_or(0) as char
+}
+
 // Represents a lexer state.
 #[derive(Copy, Clone)]
 pub struct LexerState {
@@ -57,13 +370,36 @@ pub struct Lexer {
 
 	// The string being parsed
 	string: String,
+
+	// The radix used for number literals with no explicit prefix
+	default_radix: u32,
+
+	// Errors recorded while recovering from bad input instead of halting
+	diagnostics: Vec<Diagnostic>,
+
+	// The encoding the source was decoded from (UTF-8 for lexers built
+	// directly from a &str via `new`)
+	encoding: &'static Encoding,
+
+	// Where to pull more text from when lexing runs off the end of
+	// `string` mid-construct (unset for lexers built from a whole &str)
+	source: Option<Box<dyn LexRead>>,
+
+	// Set when the current token was cut short by the end of the buffer
+	// and could still be completed by more input from `source`
+	needs_more_input: bool,
+
+	// The result of the last `peek()` call, together with the state it
+	// left the lexer in, cached so a `peek()` followed by the matching
+	// `next()` only lexes the token once. `self.state` itself is left
+	// where it was before the peek, so `save()`/`recall()` still see the
+	// pre-peek position.
+	peeked: Option<(Token, LexerState)>,
 }
 
 impl Lexer {
 	// Creates a new lexer
 	pub fn new(filename: &str, string: &str) -> Lexer {
-		let mut string = String::from(string);
-		string.push(' ');
 		Lexer {
 			filename: String::from(filename),
 			state: LexerState {
@@ -71,43 +407,135 @@ impl Lexer {
 				lino: 1,
 				charpos: 0,
 			},
-			string,
+			string: String::from(string),
+			default_radix: 8,
+			diagnostics: Vec::new(),
+			encoding: encoding_rs::UTF_8,
+			source: None,
+			needs_more_input: false,
+			peeked: None,
 		}
 	}
 
+	// Creates a lexer that pulls more source text on demand from `source`
+	// instead of owning it all up front, so a REPL can read one
+	// instruction at a time while keeping line numbers coherent across
+	// reads.
+	pub fn new_interactive(filename: &str, source: Box<dyn LexRead>) -> Lexer {
+		let mut lexer = Lexer::new(filename, "");
+		lexer.source = Some(source);
+		lexer
+	}
+
+	// Reads a source file from disk, auto-detecting its encoding
+	pub fn from_path<P: AsRef<Path>>(path: P) -> io::Result<Lexer> {
+		let path = path.as_ref();
+		let bytes = fs::read(path)?;
+		let filename = path.to_string_lossy().into_owned();
+		Ok(Lexer::from_bytes(&filename, &bytes))
+	}
+
+	// Builds a lexer from raw bytes, sniffing the encoding with chardetng
+	// (falling back to UTF-8 when detection is inconclusive) and stripping
+	// a leading BOM if the detected encoding has one
+	pub fn from_bytes(filename: &str, bytes: &[u8]) -> Lexer {
+		let mut detector = EncodingDetector::new();
+		detector.feed(bytes, true);
+		let guessed = detector.guess(None, true);
+
+		let (text, encoding, _had_errors) = guessed.decode(bytes);
+
+		let mut lexer = Lexer::new(filename, &text);
+		lexer.encoding = encoding;
+		lexer
+	}
+
+	// Sets the radix assumed for number literals with no explicit
+	// 0x/0b/0o/0d prefix (defaults to 8, i.e. bare octal).
+	pub fn set_default_radix(&mut self, radix: u32) {
+		self.default_radix = radix;
+	}
+
+	// Returns the encoding the source was decoded from
+	pub fn get_encoding(&self) -> &'static Encoding {
+		self.encoding
+	}
+
+	// Takes all diagnostics collected while recovering from lex errors so
+	// far, leaving the lexer's own list empty.
+	pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+		std::mem::take(&mut self.diagnostics)
+	}
+
+	// Returns the source text covered by a span
+	pub fn span_text(&self, span: Span) -> &str {
+		&self.string[span.start..span.end]
+	}
+
+	// Asks `source` for more text and appends it to `string`. Returns
+	// false (leaving `string` untouched) if there's no source or it has
+	// nothing left to give.
+	fn pull_more_input(&mut self, prompt: PromptStyle) -> bool {
+		let more = match self.source.as_mut() {
+			Some(source) => source.read(prompt),
+			None => return false,
+		};
+
+		if more.is_empty() {
+			return false;
+		}
+
+		self.string.push_str(&more);
+		true
+	}
+
     pub fn eof(&self) -> bool {
         self.state.pos >= self.string.len()
     }
 
-	// Returns the next token without updating the iterator
+	// Returns the next token without consuming it, caching the result so
+	// the matching `next()` call doesn't re-lex it. `self.state` is left
+	// exactly where it was before the peek, so `save()` still captures
+	// the pre-peek position and the token isn't lost.
 	pub fn peek(&mut self) -> Option<Token> {
-		let state = self.state;
-		let token = self.next();
-		self.state = state;
-		token
+		if self.peeked.is_none() {
+			let pre_state = self.state;
+			let token = self.next()?;
+			self.peeked = Some((token, self.state));
+			self.state = pre_state;
+		}
+
+		self.peeked.as_ref().map(|(token, _)| token.clone())
 	}
 
 	// Skips whitespace
 	fn skip_whitespace(&mut self) {
 		let mut in_comment = false;
+		let mut cursor = Cursor::new(&self.string[self.state.pos..]);
+
+		loop {
+			if cursor.is_eof() {
+				break;
+			}
+
+			let c = cursor.first();
 
-		for c in self.string[self.state.pos..].char_indices() {
 			// Comments end with a newline
-			if in_comment && c.1 == '\n' {
-				self.state.pos += 1;
+			if in_comment && c == '\n' {
+				cursor.bump();
 				self.state.charpos = 0;
 				self.state.lino += 1;
 				in_comment = false
 
 			// Skip whitespace and comments
-			} else if c.1 == ' ' || c.1 == '\t' || in_comment {
-				self.state.pos += 1;
+			} else if c == ' ' || c == '\t' || in_comment {
+				cursor.bump();
 				self.state.charpos += 1;
 
 			// Mark semicolons as the start of a comment
-			} else if c.1 == ';' {
+			} else if c == ';' {
 				in_comment = true;
-				self.state.pos += 1;
+				cursor.bump();
 				self.state.charpos += 1;
 
 			// Stop skipping if there's no more comments or whitespace
@@ -115,6 +543,8 @@ impl Lexer {
 				break;
 			}
 		}
+
+		self.state.pos += cursor.pos_within_token();
 	}
 
 	pub fn get_lino(&self) -> usize {
@@ -131,143 +561,312 @@ impl Lexer {
 
 	pub fn recall(&mut self, state: LexerState) {
 		self.state = state;
+		self.peeked = None;
 	}
 }
 
-impl Iterator for Lexer {
-	type Item = Token;
+impl Lexer {
+	// Lexes a single token, which may be a `TokenValue::Err` describing a
+	// problem at the current position. Used by `Iterator::next`, which
+	// recovers from errors instead of surfacing them to callers directly.
+	fn lex_one(&mut self) -> Option<Token> {
+		self.needs_more_input = false;
 
-	fn next(&mut self) -> Option<Token> {
 		// Skip whitespace
 		self.skip_whitespace();
 
-		// The token we will eventually return
-		let mut token = Token {
-			pos: self.state.pos,
-			lino: self.state.lino,
-			charpos: self.state.charpos,
-			value: TokenValue::None,
+		let start = self.state;
+		let mut cursor = Cursor::new(&self.string[start.pos..]);
+
+		if cursor.is_eof() {
+			return None;
+		}
+
+		// Number literals are dispatched by looking rather than consuming:
+		// `scan_number` itself needs to see the leading digit *and* the
+		// character after it to decide whether there's a radix prefix
+		// before committing to consuming either.
+		let first = cursor.first();
+
+		let mut needs_more_input = false;
+
+		let value = match first {
+			'(' => { cursor.bump(); TokenValue::LParen }
+			')' => { cursor.bump(); TokenValue::RParen }
+			':' => { cursor.bump(); TokenValue::Colon }
+			',' => { cursor.bump(); TokenValue::Comma }
+			'\n' => { cursor.bump(); TokenValue::Newline }
+			'<' => { cursor.bump(); TokenValue::LT }
+			'>' => { cursor.bump(); TokenValue::GT }
+			'.' => { cursor.bump(); TokenValue::Dot }
+
+			// Symbols
+			'a'..='z' | 'A'..='Z' | '_' => {
+				cursor.bump();
+				scan_symbol(&mut cursor, first)
+			}
+
+			// Number literals
+			'0'..='9' => scan_number(&mut cursor, self.default_radix),
+
+			// Strings
+			'"' => {
+				cursor.bump();
+				let (value, more) = scan_string(&mut cursor, start.lino);
+				needs_more_input = more;
+				value
+			}
+
+			// Anything else is an invalid single character
+			other => {
+				cursor.bump();
+				TokenValue::Err(format!("Invalid token '{}'", other))
+			}
 		};
 
-		// Iterate over the characters of the string
-		for c in self.string[self.state.pos..].char_indices() {
-			match &mut token.value {
-				// No type has been assigned to the token
-				TokenValue::None => {
-					// Error token (unknown character)
-					if c.0 != 0 {
-						token.value = TokenValue::Err(format!(
-							"Invalid token '{}'",
-							&self.string[self.state.pos..self.state.pos + c.0]
-						));
-						self.state.pos += c.0;
-						break;
-
-					// Symbol characters and newline
-					} else if c.1 == '(' {
-						token.value = TokenValue::LParen;
-					} else if c.1 == ')' {
-						token.value = TokenValue::RParen;
-					} else if c.1 == ':' {
-						token.value = TokenValue::Colon;
-					} else if c.1 == ',' {
-						token.value = TokenValue::Comma;
-					} else if c.1 == '\n' {
-						token.value = TokenValue::Newline;
-
-						// Update lines
-						self.state.charpos = 0;
-						self.state.lino += 1;
-					} else if c.1 == '<' {
-						token.value = TokenValue::LT;
-					} else if c.1 == '>' {
-						token.value = TokenValue::GT;
-					} else if c.1 == '.' {
-						token.value = TokenValue::Dot;
-
-					// Symbols
-					} else if ('a' <= c.1 && c.1 <= 'z') || ('A' <= c.1 && c.1 <= 'Z') || c.1 == '_'
-					{
-						token.value = TokenValue::Symbol(String::new());
-
-					// Number literals
-					} else if '0' <= c.1 && c.1 <= '7' {
-                        token.value = TokenValue::U12(0);
-
-					// Strings
-					} else if c.1 == '"' {
-						token.value = TokenValue::String(String::new());
-					}
-				}
+		let consumed = &self.string[start.pos..start.pos + cursor.pos_within_token()];
+		self.state.pos = start.pos + cursor.pos_within_token();
 
-				TokenValue::Symbol(s) => {
-					if !(('a' <= c.1 && c.1 <= 'z')
-						|| ('A' <= c.1 && c.1 <= 'Z')
-						|| ('0' <= c.1 && c.1 <= '9')
-						|| c.1 == '_')
-					{
-						s.push_str(&self.string[self.state.pos..self.state.pos + c.0]);
-						self.state.pos += c.0;
-						break;
-					}
-				}
+		if value == TokenValue::Newline {
+			self.state.charpos = 0;
+			self.state.lino += 1;
+		} else {
+			self.state.charpos += consumed.chars().count();
+		}
 
-				TokenValue::U12(v) => {
-					if !('0' <= c.1 && c.1 <= '7') {
-						// Parse
-						let string = &self.string[self.state.pos..self.state.pos + c.0];
-						let parsed = u16::from_str_radix(string, 8);
-
-						// Check for overflow
-						match parsed {
-                            Ok(n) if n < 2u16.pow(9) => token.value = TokenValue::U9(n),
-							Ok(n) if n < 2u16.pow(12) => *v = n,
-							_ => {
-								token.value = TokenValue::Err(format!(
-									"'{}' is an invalid 12 bit integer",
-									string
-								));
-							}
-						}
-
-						// Exit the loop
-						self.state.pos += c.0;
-						break;
-					}
-				}
+		self.needs_more_input = needs_more_input;
+
+		Some(Token {
+			pos: start.pos,
+			lino: start.lino,
+			charpos: start.charpos,
+			span: Span {
+				start: start.pos,
+				end: self.state.pos,
+				lino: start.lino,
+			},
+			value,
+		})
+	}
 
-				TokenValue::String(s) => {
-					if c.1 == '"' {
-						self.state.pos += c.0 + 1;
-						break;
-					} else if c.0 == self.string.len() - self.state.pos - 1 {
-						token.value = TokenValue::Err(String::from(
-							&self.string[self.state.pos..self.state.pos + c.0],
-						));
-						self.state.pos += c.0;
-						break;
-					} else {
-						s.push(c.1);
-					}
+	// Calls `lex_one`, pulling more text from an interactive `source` and
+	// retrying when the buffer is exhausted before a token starts or a
+	// string literal is still open at its end. A symbol or number that ends
+	// at the end of the buffer is returned as it is.
+	fn lex_one_with_continuation(&mut self) -> Option<Token> {
+		loop {
+			let token_start = self.state;
+			let token = self.lex_one();
+
+			if token.is_none() {
+				if self.pull_more_input(PromptStyle::First) {
+					self.state = token_start;
+					continue;
 				}
+				return None;
+			}
 
-				// Type of the token is only one character
-				_ => {
-					self.state.pos += c.0;
-					break;
+			if self.needs_more_input {
+				self.needs_more_input = false;
+				if self.pull_more_input(PromptStyle::Continuation) {
+					self.state = token_start;
+					continue;
 				}
 			}
 
-			// Update char position if not newline
-			if token.value != TokenValue::Newline {
-				self.state.charpos += 1;
+			return token;
+		}
+	}
+}
+
+impl Iterator for Lexer {
+	type Item = Token;
+
+	fn next(&mut self) -> Option<Token> {
+		if let Some((token, post_state)) = self.peeked.take() {
+			self.state = post_state;
+			return Some(token);
+		}
+
+		// Keep lexing past errors, recording each as a diagnostic, so a
+		// caller driving the iterator to completion sees every valid token
+		// (and can later pull every error via `take_diagnostics`) instead
+		// of stopping at the first bad character.
+		loop {
+			let token = self.lex_one_with_continuation()?;
+
+			if let TokenValue::Err(message) = token.value {
+				self.diagnostics.push(Diagnostic {
+					pos: token.pos,
+					lino: token.lino,
+					charpos: token.charpos,
+					message,
+				});
+				continue;
 			}
+
+			return Some(token);
 		}
+	}
+}
 
-		if token.value == TokenValue::None {
-			None
-		} else {
-			Some(token)
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn values(lexer: impl Iterator<Item = Token>) -> Vec<TokenValue> {
+		lexer.map(|t| t.value).collect()
+	}
+
+	#[test]
+	fn bare_octal_digit_is_u9() {
+		let mut lexer = Lexer::new("t", "0");
+		assert_eq!(lexer.next().unwrap().value, TokenValue::U9(0));
+	}
+
+	#[test]
+	fn radix_prefixes() {
+		let lexer = Lexer::new("t", "0x1F 0b101 0o17 0d9");
+		assert_eq!(
+			values(lexer),
+			vec![
+				TokenValue::U9(0x1F),
+				TokenValue::U9(0b101),
+				TokenValue::U9(0o17),
+				TokenValue::U9(9),
+			]
+		);
+	}
+
+	#[test]
+	fn empty_digit_run_after_prefix_is_an_error() {
+		let mut lexer = Lexer::new("t", "0x");
+		lexer.next();
+		let diagnostics = lexer.take_diagnostics();
+		assert_eq!(diagnostics.len(), 1);
+		assert!(diagnostics[0].message.contains("expected digits"));
+	}
+
+	#[test]
+	fn digit_outside_radix_is_an_error_not_silent_truncation() {
+		let mut lexer = Lexer::new("t", "08");
+		lexer.next();
+		let diagnostics = lexer.take_diagnostics();
+		assert_eq!(diagnostics.len(), 1);
+		assert!(diagnostics[0].message.contains("not a valid digit"));
+	}
+
+	#[test]
+	fn string_escapes_decode() {
+		let mut lexer = Lexer::new("t", r#""a\nb\x41\101\0\012""#);
+		assert_eq!(
+			lexer.next().unwrap().value,
+			TokenValue::String("a\nbAA\0\n".to_string())
+		);
+	}
+
+	#[test]
+	fn unterminated_string_reports_opening_line() {
+		let mut lexer = Lexer::new("t", "\n\"abc");
+		lexer.next(); // Newline
+		lexer.next(); // the unterminated string, recorded as a diagnostic
+		let diagnostics = lexer.take_diagnostics();
+		assert_eq!(diagnostics.len(), 1);
+		assert!(diagnostics[0].message.contains("starting on line 2"));
+	}
+
+	#[test]
+	fn bad_escape_does_not_leak_phantom_tokens() {
+		// A single bad escape should be one diagnostic and should not
+		// spill the rest of the literal out as separate tokens, nor make
+		// the closing quote look like it opens a new string.
+		let mut lexer = Lexer::new("t", r#""ab\qcd" foo"#);
+		assert_eq!(values(lexer.by_ref()), vec![TokenValue::Symbol("foo".to_string())]);
+		let diagnostics = lexer.take_diagnostics();
+		assert_eq!(diagnostics.len(), 1);
+		assert!(diagnostics[0].message.contains("unknown escape"));
+	}
+
+	#[test]
+	fn multiple_bad_chars_all_recorded_and_lexing_continues() {
+		let mut lexer = Lexer::new("t", "foo $ bar % baz");
+		assert_eq!(
+			values(lexer.by_ref()),
+			vec![
+				TokenValue::Symbol("foo".to_string()),
+				TokenValue::Symbol("bar".to_string()),
+				TokenValue::Symbol("baz".to_string()),
+			]
+		);
+		assert_eq!(lexer.take_diagnostics().len(), 2);
+	}
+
+	#[test]
+	fn span_text_covers_whole_token() {
+		let mut lexer = Lexer::new("t", "foobar 0x1F");
+		let first = lexer.next().unwrap();
+		assert_eq!(lexer.span_text(first.span), "foobar");
+		let second = lexer.next().unwrap();
+		assert_eq!(lexer.span_text(second.span), "0x1F");
+	}
+
+	#[test]
+	fn peek_does_not_disturb_save_point() {
+		let mut lexer = Lexer::new("t", "foo bar");
+		let saved = lexer.save();
+		assert_eq!(lexer.peek().unwrap().value, TokenValue::Symbol("foo".to_string()));
+		lexer.recall(saved);
+		assert_eq!(lexer.next().unwrap().value, TokenValue::Symbol("foo".to_string()));
+		assert_eq!(lexer.next().unwrap().value, TokenValue::Symbol("bar".to_string()));
+	}
+
+	#[test]
+	fn recall_to_saved_point_replays_peeked_token() {
+		let mut lexer = Lexer::new("t", "foo bar");
+		let saved = lexer.save();
+		let peeked = lexer.peek();
+		lexer.recall(saved);
+		assert_eq!(lexer.next().map(|t| t.value), peeked.map(|t| t.value));
+	}
+
+	#[test]
+	fn recall_past_a_peek_discards_the_stale_cached_token() {
+		let mut lexer = Lexer::new("t", "foo bar baz");
+		let saved = lexer.save();
+		lexer.next(); // consume "foo", landing the lexer right before "bar"
+		lexer.peek(); // caches "bar" without moving state
+		lexer.recall(saved); // jump back to before "foo", well short of "bar"
+		assert_eq!(lexer.next().unwrap().value, TokenValue::Symbol("foo".to_string()));
+		assert_eq!(lexer.next().unwrap().value, TokenValue::Symbol("bar".to_string()));
+	}
+
+	struct FeedReader {
+		parts: Vec<String>,
+	}
+
+	impl LexRead for FeedReader {
+		fn read(&mut self, _prompt: PromptStyle) -> String {
+			if self.parts.is_empty() {
+				String::new()
+			} else {
+				self.parts.remove(0)
+			}
 		}
 	}
+
+	#[test]
+	fn interactive_source_completes_a_literal_split_across_reads() {
+		let reader = FeedReader {
+			parts: vec!["\"bar\nba".to_string(), "z\" qux\n".to_string()],
+		};
+		let lexer = Lexer::new_interactive("t", Box::new(reader));
+		assert_eq!(
+			values(lexer),
+			vec![
+				TokenValue::String("bar\nbaz".to_string()),
+				TokenValue::Symbol("qux".to_string()),
+				TokenValue::Newline,
+			]
+		);
+	}
 }